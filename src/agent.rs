@@ -1,13 +1,17 @@
+use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BytesMut;
 use log::{error, info};
+use rustls::ServerConfig;
 use tokio::net::TcpListener;
 use tokio::net::UnixListener;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
 use std::error::Error;
 use std::fmt::Debug;
 use std::future::Future;
+use std::io;
 use std::mem::size_of;
 use std::net::SocketAddr;
 use std::path::Path;
@@ -24,7 +28,35 @@ use super::error::AgentError;
 use super::proto::message::Message;
 use super::proto::{from_bytes, to_bytes};
 
-struct MessageCodec;
+/// Upper bound on the length prefix `MessageCodec` will accept before the
+/// rest of the message has even arrived. 256 KiB comfortably covers the
+/// largest legitimate SSH-agent messages (e.g. signing requests with large
+/// certificates) while still rejecting a peer that announces an absurd
+/// length and then dribbles bytes in to exhaust memory.
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 256 * 1024;
+
+pub(crate) struct MessageCodec {
+    max_length: usize,
+}
+
+impl MessageCodec {
+    pub(crate) fn new() -> Self {
+        Self::with_max_length(DEFAULT_MAX_MESSAGE_LENGTH)
+    }
+
+    /// Builds a codec that rejects any incoming message whose declared length
+    /// exceeds `max_length`, instead of buffering indefinitely while waiting
+    /// for the rest of it to arrive.
+    pub(crate) fn with_max_length(max_length: usize) -> Self {
+        Self { max_length }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Decoder for MessageCodec {
     type Item = Message;
@@ -39,6 +71,13 @@ impl Decoder for MessageCodec {
 
         let length = bytes.read_u32::<BigEndian>()? as usize;
 
+        if length > self.max_length {
+            return Err(AgentError::MessageTooLong {
+                length,
+                max_length: self.max_length,
+            });
+        }
+
         if bytes.len() < length {
             return Ok(None);
         }
@@ -59,61 +98,339 @@ impl Encoder<Message> for MessageCodec {
     }
 }
 
+/// Identifies the peer on the other end of an accepted connection, so an
+/// [`Agent`] implementation can base authorization decisions on *who*
+/// connected (e.g. only sign for a particular uid, or log the requesting
+/// process) rather than only on what they asked for.
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectionContext {
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+    Tcp {
+        peer_addr: SocketAddr,
+    },
+}
+
+impl ConnectionContext {
+    fn for_unix(socket: &tokio::net::UnixStream) -> io::Result<Self> {
+        let cred = socket.peer_cred()?;
+        Ok(ConnectionContext::Unix {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+
+    fn for_tcp(socket: &tokio::net::TcpStream) -> io::Result<Self> {
+        Ok(ConnectionContext::Tcp {
+            peer_addr: socket.peer_addr()?,
+        })
+    }
+}
+
+/// A shutdown future that resolves once the process receives `SIGINT`
+/// (Ctrl-C), for passing to [`Agent::serve_until`] / [`Agent::run_unix_until`]
+/// and friends so an embedded agent can be torn down cleanly instead of
+/// killed out from under an in-flight connection.
+pub async fn ctrl_c_shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 macro_rules! handle_clients {
-    ($self:ident, $socket:ident) => {{
+    ($self:ident, $socket:ident, $make_ctx:expr, $shutdown:ident) => {{
         use futures::FutureExt;
         use futures::TryFutureExt;
         info!("Listening; socket = {:?}", $socket);
         let arc_self = Arc::new($self);
-        $socket
-            .incoming()
-            .map_err(|e| error!("Failed to accept socket; error = {:?}", e))
-            .for_each(move |socket| {
-                let (write, read) = Framed::new(socket, MessageCodec).split();
-                let arc_self = arc_self.clone();
-                let connection = write
-                    .send_all(read.and_then(move |message| {
-                        arc_self.handle_async(message).map_err(|e| {
-                            error!("Error handling message; error = {:?}", e);
-                            AgentError::User
-                        })
-                    }))
-                    .map(|_| ())
-                    .map_err(|e| error!("Error while handling message; error = {:?}", e));
-                tokio::spawn(connection)
-            })
-            .map_err(|e| e.into())
+        let mut incoming = $socket.incoming();
+        tokio::pin!($shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut $shutdown => {
+                    info!("Shutdown requested; no longer accepting connections");
+                    break;
+                }
+                accepted = incoming.next() => {
+                    let socket = match accepted {
+                        Some(Ok(socket)) => socket,
+                        Some(Err(e)) => {
+                            error!("Failed to accept socket; error = {:?}", e);
+                            continue;
+                        }
+                        None => break,
+                    };
+                    let arc_self = arc_self.clone();
+                    match $make_ctx(&socket) {
+                        Ok(ctx) => {
+                            let (write, read) = Framed::new(socket, MessageCodec::new()).split();
+                            let connection = write
+                                .send_all(read.and_then(move |message| {
+                                    arc_self.handle_async_with_context(message, &ctx).map_err(|e| {
+                                        error!("Error handling message; error = {:?}", e);
+                                        AgentError::User
+                                    })
+                                }))
+                                .map(|_| ())
+                                .map_err(|e| error!("Error while handling message; error = {:?}", e));
+                            tokio::spawn(connection);
+                        }
+                        Err(e) => error!("Failed to read peer credentials; error = {:?}", e),
+                    }
+                }
+            }
+        }
+        Ok(())
     }};
 }
 
+macro_rules! handle_clients_tls {
+    ($self:ident, $socket:ident, $acceptor:ident, $make_ctx:expr, $shutdown:ident) => {{
+        use futures::FutureExt;
+        use futures::TryFutureExt;
+        info!("Listening (TLS); socket = {:?}", $socket);
+        let arc_self = Arc::new($self);
+        let mut incoming = $socket.incoming();
+        tokio::pin!($shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut $shutdown => {
+                    info!("Shutdown requested; no longer accepting connections");
+                    break;
+                }
+                accepted = incoming.next() => {
+                    let socket = match accepted {
+                        Some(Ok(socket)) => socket,
+                        Some(Err(e)) => {
+                            error!("Failed to accept socket; error = {:?}", e);
+                            continue;
+                        }
+                        None => break,
+                    };
+                    let arc_self = arc_self.clone();
+                    let acceptor = $acceptor.clone();
+                    let ctx = $make_ctx(&socket);
+                    tokio::spawn(async move {
+                        let ctx = match ctx {
+                            Ok(ctx) => ctx,
+                            Err(e) => return error!("Failed to read peer credentials; error = {:?}", e),
+                        };
+                        let socket = match acceptor.accept(socket).await {
+                            Ok(socket) => socket,
+                            Err(e) => return error!("TLS handshake failed; error = {:?}", e),
+                        };
+                        let (write, read) = Framed::new(socket, MessageCodec::new()).split();
+                        let _ = write
+                            .send_all(read.and_then(move |message| {
+                                arc_self.handle_async_with_context(message, &ctx).map_err(|e| {
+                                    error!("Error handling message; error = {:?}", e);
+                                    AgentError::User
+                                })
+                            }))
+                            .map(|_| ())
+                            .map_err(|e| error!("Error while handling message; error = {:?}", e))
+                            .await;
+                    });
+                }
+            }
+        }
+        Ok(())
+    }};
+}
+
+#[async_trait]
 pub trait Agent: 'static + Sync + Send + Sized {
     type Error: Debug + Send + Sync;
 
     fn handle(&self, message: Message) -> Result<Message, Self::Error>;
 
-    fn handle_async(
+    async fn handle_async(&self, message: Message) -> Result<Message, Self::Error> {
+        self.handle(message)
+    }
+
+    /// Like [`handle_async`](Agent::handle_async), but with access to the
+    /// credentials of the peer that sent `message`. This is the one override
+    /// point for context-aware dispatch: the connection loop always calls
+    /// this method, and its default ignores `ctx` and defers to
+    /// `handle_async`, so implementations that don't care about peer
+    /// identity keep working unchanged.
+    async fn handle_async_with_context(
         &self,
         message: Message,
-    ) -> Box<dyn Future<Output = Result<Message, Self::Error>> + Send + Sync> {
-        Box::new(self.handle(message))
+        ctx: &ConnectionContext,
+    ) -> Result<Message, Self::Error> {
+        let _ = ctx;
+        self.handle_async(message).await
     }
 
-    #[allow(clippy::unit_arg)]
+    /// Drives the agent protocol over every connection accepted from `socket`
+    /// until `shutdown` resolves, at which point the accept loop stops (any
+    /// already-spawned connections keep running to completion). Unlike
+    /// [`run_listener`](Agent::run_listener), this returns a plain future so
+    /// callers that already own a Tokio runtime can `.await` or `tokio::spawn`
+    /// it instead of handing control to an internally-created one.
+    async fn serve_until(
+        self,
+        socket: UnixListener,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        handle_clients!(self, socket, ConnectionContext::for_unix, shutdown)
+    }
+
+    /// Like [`serve_until`](Agent::serve_until), but never stops accepting
+    /// connections on its own.
+    async fn serve(self, socket: UnixListener) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serve_until(socket, futures::future::pending()).await
+    }
+
+    /// Like [`serve_until`](Agent::serve_until), but binds and serves a
+    /// plaintext TCP listener.
+    async fn serve_tcp_until(
+        self,
+        addr: &str,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let socket = TcpListener::bind(&addr.parse::<SocketAddr>()?)?;
+        handle_clients!(self, socket, ConnectionContext::for_tcp, shutdown)
+    }
+
+    /// Like [`serve_tcp_until`](Agent::serve_tcp_until), but never stops
+    /// accepting connections on its own.
+    async fn serve_tcp(self, addr: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serve_tcp_until(addr, futures::future::pending()).await
+    }
+
+    /// Like [`serve_tcp_until`](Agent::serve_tcp_until), but wraps each accepted
+    /// connection in a TLS handshake using the given `server_config` before
+    /// driving the agent protocol over it, so the socket is authenticated and
+    /// encrypted instead of plaintext.
+    async fn serve_tcp_tls_until(
+        self,
+        addr: &str,
+        server_config: Arc<ServerConfig>,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let socket = TcpListener::bind(&addr.parse::<SocketAddr>()?)?;
+        let acceptor = TlsAcceptor::from(server_config);
+        handle_clients_tls!(self, socket, acceptor, ConnectionContext::for_tcp, shutdown)
+    }
+
+    /// Like [`serve_tcp_tls_until`](Agent::serve_tcp_tls_until), but never
+    /// stops accepting connections on its own.
+    async fn serve_tcp_tls(
+        self,
+        addr: &str,
+        server_config: Arc<ServerConfig>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serve_tcp_tls_until(addr, server_config, futures::future::pending())
+            .await
+    }
+
+    /// Blocking wrapper around [`serve`](Agent::serve) for callers that don't
+    /// already have a Tokio runtime.
     fn run_listener(self, socket: UnixListener) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let res = rt.block_on(handle_clients!(self, socket));
-        Ok(res)
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(self.serve(socket))
+    }
+
+    /// Binds a Unix socket at `path` and serves it until `shutdown` resolves
+    /// (for example [`ctrl_c_shutdown`]), removing the socket file afterwards
+    /// so a restarted agent doesn't fail to bind over a stale one.
+    fn run_unix_until(
+        self,
+        path: impl AsRef<Path>,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+        let socket = UnixListener::bind(path)?;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(self.serve_until(socket, shutdown));
+        let _ = std::fs::remove_file(path);
+        result
     }
 
+    /// Binds and serves a Unix socket at `path`, removing the socket file on
+    /// exit. Unlike [`run_unix_until`](Agent::run_unix_until), the accept loop
+    /// never stops on its own (and so neither does the cleanup run).
     fn run_unix(self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error + Send + Sync>> {
-        self.run_listener(UnixListener::bind(path)?)
+        self.run_unix_until(path, futures::future::pending())
     }
 
-    #[allow(clippy::unit_arg)]
+    /// Blocking wrapper around [`serve_tcp_until`](Agent::serve_tcp_until) for
+    /// callers that don't already have a Tokio runtime.
+    fn run_tcp_until(
+        self,
+        addr: &str,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(self.serve_tcp_until(addr, shutdown))
+    }
+
+    /// Blocking wrapper around [`serve_tcp`](Agent::serve_tcp) for callers that
+    /// don't already have a Tokio runtime.
     fn run_tcp(self, addr: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let socket = TcpListener::bind(&addr.parse::<SocketAddr>()?)?;
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let res = rt.block_on(handle_clients!(self, socket));
-        Ok(res)
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(self.serve_tcp(addr))
+    }
+
+    /// Blocking wrapper around [`serve_tcp_tls_until`](Agent::serve_tcp_tls_until)
+    /// for callers that don't already have a Tokio runtime.
+    fn run_tcp_tls_until(
+        self,
+        addr: &str,
+        server_config: Arc<ServerConfig>,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(self.serve_tcp_tls_until(addr, server_config, shutdown))
+    }
+
+    /// Blocking wrapper around [`serve_tcp_tls`](Agent::serve_tcp_tls) for callers
+    /// that don't already have a Tokio runtime.
+    fn run_tcp_tls(
+        self,
+        addr: &str,
+        server_config: Arc<ServerConfig>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(self.serve_tcp_tls(addr, server_config))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_rejects_messages_over_the_length_cap() {
+        let mut codec = MessageCodec::with_max_length(8);
+        let mut src = BytesMut::new();
+        src.put_u32(9);
+        src.put_slice(&[0u8; 9]);
+
+        let result = codec.decode(&mut src);
+        assert!(matches!(
+            result,
+            Err(AgentError::MessageTooLong {
+                length: 9,
+                max_length: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_accepts_messages_at_the_length_cap() {
+        let mut buf = BytesMut::new();
+        MessageCodec::new()
+            .encode(Message::RequestIdentities, &mut buf)
+            .expect("encoding a well-formed message should not fail");
+
+        let mut codec = MessageCodec::with_max_length(buf.len() - size_of::<u32>());
+        let result = codec.decode(&mut buf);
+        assert!(matches!(result, Ok(Some(Message::RequestIdentities))));
     }
 }
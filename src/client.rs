@@ -0,0 +1,79 @@
+use futures::SinkExt;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_util::codec::Framed;
+
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use super::agent::MessageCodec;
+use super::error::AgentError;
+use super::proto::message::{Identity, Message, SignRequest, Signature};
+
+/// The client half of the SSH-agent protocol: connects to an existing agent
+/// and issues requests against it, reusing the same [`MessageCodec`] the
+/// [`Agent`](super::agent::Agent) trait serves with. This is the building
+/// block for agent-proxy and agent-forwarding tools that need to sit in
+/// front of a real agent rather than implement one.
+pub struct AgentClient<T> {
+    framed: Framed<T, MessageCodec>,
+}
+
+impl AgentClient<UnixStream> {
+    /// Connects to an agent listening on the Unix socket at `path`.
+    pub async fn connect_unix(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl AgentClient<TcpStream> {
+    /// Connects to an agent listening on the TCP address `addr`.
+    pub async fn connect_tcp(addr: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let stream = TcpStream::connect(addr.parse::<SocketAddr>()?).await?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<T> AgentClient<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: T) -> Self {
+        Self {
+            framed: Framed::new(stream, MessageCodec::new()),
+        }
+    }
+
+    async fn request(&mut self, message: Message) -> Result<Message, AgentError> {
+        self.framed.send(message).await?;
+        match self.framed.next().await {
+            Some(response) => response,
+            None => Err(AgentError::from(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "agent closed the connection",
+            ))),
+        }
+    }
+
+    /// Asks the agent for the list of identities it currently holds.
+    pub async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        match self.request(Message::RequestIdentities).await? {
+            Message::IdentitiesAnswer(identities) => Ok(identities),
+            _ => Err(AgentError::User),
+        }
+    }
+
+    /// Asks the agent to sign `request`, returning the resulting signature.
+    pub async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        match self.request(Message::SignRequest(request)).await? {
+            Message::SignResponse(signature) => Ok(signature),
+            _ => Err(AgentError::User),
+        }
+    }
+}